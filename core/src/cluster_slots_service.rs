@@ -6,6 +6,7 @@ use solana_measure::measure::Measure;
 use solana_runtime::bank_forks::BankForks;
 use solana_sdk::clock::Slot;
 use std::{
+    collections::BTreeSet,
     sync::{
         atomic::{AtomicBool, Ordering},
         {Arc, RwLock},
@@ -17,6 +18,17 @@ use std::{
 pub type ClusterSlotsUpdateReceiver = Receiver<Vec<Slot>>;
 pub type ClusterSlotsUpdateSender = Sender<Vec<Slot>>;
 
+// Default cap on the number of slots drained from `cluster_slots_update_receiver` and
+// pushed to gossip in a single cycle of `run`. Keeps memory and epoch-slots message size
+// bounded when repair is pushing updates faster than they can be coalesced. Overridable
+// via `ClusterSlotsService::new_with_batch_size`'s `max_epoch_slots_batch_size` argument.
+const DEFAULT_EPOCH_SLOTS_BATCH_SIZE: usize = 2000;
+
+// How far below the current root `initialize_epoch_slots` scans the blockstore for
+// rooted slots at startup. Bounds the startup scan (and the resulting gossip push) to a
+// fixed window instead of walking the whole ledger back to genesis on long-lived chains.
+const EPOCH_SLOTS_INIT_LOOKBACK_SLOTS: Slot = 1000;
+
 #[derive(Default, Debug)]
 struct ClusterSlotsServiceTiming {
     pub lowest_slot_elapsed: u64,
@@ -30,8 +42,33 @@ impl ClusterSlotsServiceTiming {
     }
 }
 
+/// A snapshot of the last values `ClusterSlotsService` pushed into gossip, so other
+/// subsystems (e.g. RPC, repair) can check how far behind this node's advertised
+/// slot state is without reaching into gossip CRDS internals themselves.
+#[derive(Clone, Debug)]
+pub struct ClusterSlotsServiceStatus {
+    pub lowest_slot: Slot,
+    pub last_batch_size: usize,
+    /// When `lowest_slot`/`new_root` were last refreshed, i.e. the end of the last
+    /// `run` tick - updated every tick, not just ticks that pushed a batch.
+    pub last_updated: Instant,
+    pub new_root: Slot,
+}
+
+impl Default for ClusterSlotsServiceStatus {
+    fn default() -> Self {
+        Self {
+            lowest_slot: Slot::default(),
+            last_batch_size: 0,
+            last_updated: Instant::now(),
+            new_root: Slot::default(),
+        }
+    }
+}
+
 pub struct ClusterSlotsService {
     t_cluster_slots_service: JoinHandle<()>,
+    status: Arc<RwLock<ClusterSlotsServiceStatus>>,
 }
 
 impl ClusterSlotsService {
@@ -42,9 +79,33 @@ impl ClusterSlotsService {
         cluster_info: Arc<ClusterInfo>,
         cluster_slots_update_receiver: ClusterSlotsUpdateReceiver,
         exit: Arc<AtomicBool>,
+    ) -> Self {
+        Self::new_with_batch_size(
+            blockstore,
+            cluster_slots,
+            bank_forks,
+            cluster_info,
+            cluster_slots_update_receiver,
+            exit,
+            DEFAULT_EPOCH_SLOTS_BATCH_SIZE,
+        )
+    }
+
+    /// Like `new`, but allows overriding the per-cycle epoch-slots batch/backpressure
+    /// bound (see `DEFAULT_EPOCH_SLOTS_BATCH_SIZE`) instead of using the default.
+    pub fn new_with_batch_size(
+        blockstore: Arc<Blockstore>,
+        cluster_slots: Arc<ClusterSlots>,
+        bank_forks: Arc<RwLock<BankForks>>,
+        cluster_info: Arc<ClusterInfo>,
+        cluster_slots_update_receiver: ClusterSlotsUpdateReceiver,
+        exit: Arc<AtomicBool>,
+        max_epoch_slots_batch_size: usize,
     ) -> Self {
         Self::initialize_lowest_slot(&blockstore, &cluster_info);
-        Self::initialize_epoch_slots(&bank_forks, &cluster_info);
+        Self::initialize_epoch_slots(&blockstore, &bank_forks, &cluster_info);
+        let status = Arc::new(RwLock::new(ClusterSlotsServiceStatus::default()));
+        let status_for_thread = status.clone();
         let t_cluster_slots_service = Builder::new()
             .name("solana-cluster-slots-service".to_string())
             .spawn(move || {
@@ -55,12 +116,15 @@ impl ClusterSlotsService {
                     cluster_info,
                     cluster_slots_update_receiver,
                     exit,
+                    status_for_thread,
+                    max_epoch_slots_batch_size,
                 )
             })
             .unwrap();
 
         ClusterSlotsService {
             t_cluster_slots_service,
+            status,
         }
     }
 
@@ -68,6 +132,11 @@ impl ClusterSlotsService {
         self.t_cluster_slots_service.join()
     }
 
+    /// Returns a snapshot of the last slot state this service pushed into gossip.
+    pub fn status(&self) -> ClusterSlotsServiceStatus {
+        self.status.read().unwrap().clone()
+    }
+
     fn run(
         blockstore: Arc<Blockstore>,
         cluster_slots: Arc<ClusterSlots>,
@@ -75,9 +144,15 @@ impl ClusterSlotsService {
         cluster_info: Arc<ClusterInfo>,
         cluster_slots_update_receiver: ClusterSlotsUpdateReceiver,
         exit: Arc<AtomicBool>,
+        status: Arc<RwLock<ClusterSlotsServiceStatus>>,
+        max_epoch_slots_batch_size: usize,
     ) {
         let mut cluster_slots_service_timing = ClusterSlotsServiceTiming::default();
         let mut last_stats = Instant::now();
+        // Slots that were drained from the channel but deferred past the per-cycle cap;
+        // carried forward so `process_cluster_slots_updates` pushes them next tick
+        // instead of losing them.
+        let mut pending_slots: Vec<Slot> = Vec::new();
         loop {
             if exit.load(Ordering::Relaxed) {
                 break;
@@ -91,6 +166,9 @@ impl ClusterSlotsService {
                     break;
                 }
             };
+            if let Some(slots) = slots {
+                pending_slots.extend(slots);
+            }
             let new_root = bank_forks.read().unwrap().root();
             let mut lowest_slot_elapsed = Measure::start("lowest_slot_elapsed");
             let lowest_slot = blockstore.lowest_slot();
@@ -98,16 +176,35 @@ impl ClusterSlotsService {
             lowest_slot_elapsed.stop();
             let mut process_cluster_slots_updates_elapsed =
                 Measure::start("process_cluster_slots_updates_elapsed");
-            if let Some(slots) = slots {
-                Self::process_cluster_slots_updates(
-                    slots,
+            let batch_size = if pending_slots.is_empty() {
+                None
+            } else {
+                let (batch_size, deferred_slots) = Self::process_cluster_slots_updates(
+                    std::mem::take(&mut pending_slots),
                     &cluster_slots_update_receiver,
                     &cluster_info,
+                    max_epoch_slots_batch_size,
                 );
-            }
+                pending_slots = deferred_slots;
+                Some(batch_size)
+            };
             cluster_slots.update(new_root, &cluster_info, &bank_forks);
             process_cluster_slots_updates_elapsed.stop();
 
+            {
+                // `lowest_slot`/`new_root` are refreshed every tick regardless of
+                // whether a batch was processed, so `last_updated` must be too -
+                // otherwise callers can't distinguish "no slots lately" from "the
+                // service is stuck" when gauging staleness of the whole snapshot.
+                let mut status = status.write().unwrap();
+                status.lowest_slot = lowest_slot;
+                status.new_root = new_root;
+                if let Some(batch_size) = batch_size {
+                    status.last_batch_size = batch_size;
+                }
+                status.last_updated = Instant::now();
+            }
+
             cluster_slots_service_timing.update(
                 lowest_slot_elapsed.as_us(),
                 process_cluster_slots_updates_elapsed.as_us(),
@@ -133,20 +230,49 @@ impl ClusterSlotsService {
         }
     }
 
+    // Returns the number of slots pushed to gossip this cycle, plus any slots that were
+    // drained over `max_batch_size` and must be carried into the next cycle instead of
+    // being dropped.
     fn process_cluster_slots_updates(
         mut slots: Vec<Slot>,
         cluster_slots_update_receiver: &ClusterSlotsUpdateReceiver,
         cluster_info: &ClusterInfo,
-    ) {
-        while let Ok(mut more) = cluster_slots_update_receiver.try_recv() {
-            slots.append(&mut more);
+        max_batch_size: usize,
+    ) -> (usize, Vec<Slot>) {
+        // Bound how much a single cycle will drain from the channel. Under heavy
+        // repair activity this keeps the vector from growing without limit; any
+        // messages still queued in the channel just wait for the next 200ms tick.
+        while slots.len() < max_batch_size {
+            match cluster_slots_update_receiver.try_recv() {
+                Ok(mut more) => slots.append(&mut more),
+                Err(_) => break,
+            }
         }
+
         #[allow(clippy::stable_sort_primitive)]
         slots.sort();
+        slots.dedup();
 
+        // A single drained message can itself push us over the cap. Defer the excess to
+        // the next cycle instead of dropping it, so epoch-slots gossip stays bounded per
+        // push without ever losing slots.
+        let deferred_slots = if slots.len() > max_batch_size {
+            slots.split_off(max_batch_size)
+        } else {
+            Vec::new()
+        };
+
+        datapoint_info!(
+            "cluster_slots_service-coalesce",
+            ("batch_size", slots.len(), i64),
+            ("deferred_slots", deferred_slots.len(), i64),
+        );
+
+        let batch_size = slots.len();
         if !slots.is_empty() {
             cluster_info.push_epoch_slots(&slots);
         }
+        (batch_size, deferred_slots)
     }
 
     fn initialize_lowest_slot(blockstore: &Blockstore, cluster_info: &ClusterInfo) {
@@ -161,24 +287,57 @@ impl ClusterSlotsService {
         cluster_info.push_lowest_slot(lowest_slot);
     }
 
-    fn initialize_epoch_slots(bank_forks: &RwLock<BankForks>, cluster_info: &ClusterInfo) {
-        // TODO: Should probably incorporate slots that were replayed on startup,
-        // and maybe some that were frozen < snapshot root in case validators restart
-        // from newer snapshots and lose history.
-        let frozen_banks = bank_forks.read().unwrap().frozen_banks();
-        let mut frozen_bank_slots: Vec<Slot> = frozen_banks.keys().cloned().collect();
-        frozen_bank_slots.sort_unstable();
+    fn initialize_epoch_slots(
+        blockstore: &Blockstore,
+        bank_forks: &RwLock<BankForks>,
+        cluster_info: &ClusterInfo,
+    ) {
+        // Incorporate both the banks frozen so far this run, and any rooted slots
+        // already present in the blockstore within a bounded window below the
+        // snapshot root (e.g. slots replayed during startup, or slots frozen before
+        // the snapshot that was loaded). Otherwise a validator restarting from a
+        // newer snapshot would advertise a truncated epoch-slots history and peers
+        // could wrongly conclude it's missing data it actually has. The lookback is
+        // bounded so this scan and the resulting gossip push stay cheap even on a
+        // long-lived chain.
+        let root = bank_forks.read().unwrap().root();
+        let frozen_bank_slots: Vec<Slot> = bank_forks
+            .read()
+            .unwrap()
+            .frozen_banks()
+            .keys()
+            .cloned()
+            .collect();
+        let lookback_root = root.saturating_sub(EPOCH_SLOTS_INIT_LOOKBACK_SLOTS);
+        let replayed_slots = match blockstore.rooted_slot_iterator(lookback_root) {
+            Ok(iter) => iter.take_while(|slot| *slot <= root).collect(),
+            Err(err) => {
+                error!(
+                    "Failed to read rooted slots from blockstore for epoch slots init: {:?}",
+                    err
+                );
+                Vec::new()
+            }
+        };
+        let epoch_slots = Self::merge_epoch_slots(frozen_bank_slots, replayed_slots);
 
-        if !frozen_bank_slots.is_empty() {
-            cluster_info.push_epoch_slots(&frozen_bank_slots);
+        if !epoch_slots.is_empty() {
+            cluster_info.push_epoch_slots(&epoch_slots);
         }
     }
+
+    fn merge_epoch_slots(frozen_bank_slots: Vec<Slot>, replayed_slots: Vec<Slot>) -> Vec<Slot> {
+        let mut slots: BTreeSet<Slot> = replayed_slots.into_iter().collect();
+        slots.extend(frozen_bank_slots);
+        slots.into_iter().collect()
+    }
 }
 
 #[cfg(test)]
 mod test {
     use {
         super::*,
+        crossbeam_channel::unbounded,
         solana_gossip::{cluster_info::Node, crds_value::CrdsValueLabel},
         solana_sdk::pubkey::Pubkey,
     };
@@ -198,4 +357,109 @@ mod test {
         };
         assert_eq!(lowest.lowest, 5);
     }
+
+    #[test]
+    fn test_merge_epoch_slots_dedup() {
+        let frozen_bank_slots = vec![5, 1, 3];
+        let replayed_slots = vec![2, 3, 4];
+        let merged = ClusterSlotsService::merge_epoch_slots(frozen_bank_slots, replayed_slots);
+        assert_eq!(merged, vec![1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn test_merge_epoch_slots_empty() {
+        let merged = ClusterSlotsService::merge_epoch_slots(vec![], vec![]);
+        assert!(merged.is_empty());
+    }
+
+    #[test]
+    fn test_merge_epoch_slots_disjoint() {
+        let frozen_bank_slots = vec![10, 11];
+        let replayed_slots = vec![1, 2];
+        let merged = ClusterSlotsService::merge_epoch_slots(frozen_bank_slots, replayed_slots);
+        assert_eq!(merged, vec![1, 2, 10, 11]);
+    }
+
+    #[test]
+    fn test_process_cluster_slots_updates_dedups() {
+        let pubkey = Pubkey::new_unique();
+        let node_info = Node::new_localhost_with_pubkey(&pubkey);
+        let cluster_info = ClusterInfo::new_with_invalid_keypair(node_info.info);
+        let (_sender, receiver) = unbounded();
+        let (batch_size, deferred_slots) = ClusterSlotsService::process_cluster_slots_updates(
+            vec![5, 1, 1, 3, 5],
+            &receiver,
+            &cluster_info,
+            DEFAULT_EPOCH_SLOTS_BATCH_SIZE,
+        );
+        assert_eq!(batch_size, 3);
+        assert!(deferred_slots.is_empty());
+        cluster_info.flush_push_queue();
+        let pushed = {
+            let label = CrdsValueLabel::EpochSlots(0, pubkey);
+            let gossip_crds = cluster_info.gossip.crds.read().unwrap();
+            gossip_crds
+                .get(&label)
+                .unwrap()
+                .value
+                .epoch_slots()
+                .unwrap()
+                .to_slots(0)
+        };
+        assert_eq!(pushed, vec![1, 3, 5]);
+    }
+
+    #[test]
+    fn test_process_cluster_slots_updates_defers_overflow() {
+        let pubkey = Pubkey::new_unique();
+        let node_info = Node::new_localhost_with_pubkey(&pubkey);
+        let cluster_info = ClusterInfo::new_with_invalid_keypair(node_info.info);
+        let (_sender, receiver) = unbounded();
+        let max_batch_size = 50;
+        let oversized_batch: Vec<Slot> = (0..max_batch_size as Slot + 10).collect();
+        let (batch_size, deferred_slots) = ClusterSlotsService::process_cluster_slots_updates(
+            oversized_batch,
+            &receiver,
+            &cluster_info,
+            max_batch_size,
+        );
+        assert_eq!(batch_size, max_batch_size);
+        // The overflow must be deferred for the next cycle, not dropped.
+        assert_eq!(deferred_slots.len(), 10);
+        assert_eq!(
+            deferred_slots,
+            (max_batch_size as Slot..max_batch_size as Slot + 10).collect::<Vec<Slot>>()
+        );
+        cluster_info.flush_push_queue();
+        let pushed = {
+            let label = CrdsValueLabel::EpochSlots(0, pubkey);
+            let gossip_crds = cluster_info.gossip.crds.read().unwrap();
+            gossip_crds
+                .get(&label)
+                .unwrap()
+                .value
+                .epoch_slots()
+                .unwrap()
+                .to_slots(0)
+        };
+        assert_eq!(pushed.len(), max_batch_size);
+
+        // Feeding the deferred slots back through on the next cycle pushes the rest.
+        let (batch_size, deferred_slots) = ClusterSlotsService::process_cluster_slots_updates(
+            deferred_slots,
+            &receiver,
+            &cluster_info,
+            max_batch_size,
+        );
+        assert_eq!(batch_size, 10);
+        assert!(deferred_slots.is_empty());
+    }
+
+    #[test]
+    fn test_cluster_slots_service_status_default() {
+        let status = ClusterSlotsServiceStatus::default();
+        assert_eq!(status.lowest_slot, 0);
+        assert_eq!(status.new_root, 0);
+        assert_eq!(status.last_batch_size, 0);
+    }
 }